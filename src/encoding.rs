@@ -0,0 +1,151 @@
+//! Encodings of file names on servers not using UTF-8, see filename_encoding
+//!
+//! FTP has no say in how names are encoded, the server just passes on the
+//! bytes of its file system. Names are kept as strings everywhere else, so
+//! they are decoded when read from the server and encoded back into the same
+//! bytes when sent to it. The single byte encodings map every byte to a
+//! character, so any name survives the round trip.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// How a server encodes file names
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FilenameEncoding {
+    /// UTF-8, names that aren't valid UTF-8 can't be transferred
+    #[default]
+    Utf8,
+    /// ISO-8859-1, each byte is the character with the same code
+    Latin1,
+    /// Windows-1251, Cyrillic names on Windows servers
+    Cp1251,
+}
+
+/// Characters of the bytes 0x80 to 0xBF in Windows-1251, the undefined 0x98
+/// stands for itself, 0xC0 to 0xFF are А to я
+#[rustfmt::skip]
+const CP1251_HIGH: [char; 64] = [
+    '\u{402}', '\u{403}', '\u{201a}', '\u{453}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{20ac}', '\u{2030}', '\u{409}', '\u{2039}', '\u{40a}', '\u{40c}', '\u{40b}', '\u{40f}',
+    '\u{452}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{98}', '\u{2122}', '\u{459}', '\u{203a}', '\u{45a}', '\u{45c}', '\u{45b}', '\u{45f}',
+    '\u{a0}', '\u{40e}', '\u{45e}', '\u{408}', '\u{a4}', '\u{490}', '\u{a6}', '\u{a7}',
+    '\u{401}', '\u{a9}', '\u{404}', '\u{ab}', '\u{ac}', '\u{ad}', '\u{ae}', '\u{407}',
+    '\u{b0}', '\u{b1}', '\u{406}', '\u{456}', '\u{491}', '\u{b5}', '\u{b6}', '\u{b7}',
+    '\u{451}', '\u{2116}', '\u{454}', '\u{bb}', '\u{458}', '\u{405}', '\u{455}', '\u{457}',
+];
+
+impl FilenameEncoding {
+    /// Decodes bytes read from the server, None if they aren't valid UTF-8
+    pub fn decode(self, bytes: &[u8]) -> Option<String> {
+        match self {
+            FilenameEncoding::Utf8 => String::from_utf8(bytes.to_vec()).ok(),
+            FilenameEncoding::Latin1 => Some(bytes.iter().map(|&b| char::from(b)).collect()),
+            FilenameEncoding::Cp1251 => Some(
+                bytes
+                    .iter()
+                    .map(|&b| match b {
+                        0..=0x7f => char::from(b),
+                        0x80..=0xbf => CP1251_HIGH[usize::from(b - 0x80)],
+                        _ => char::from_u32(0x410 + u32::from(b - 0xc0)).unwrap(),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Decodes a reply of the server, replacing what can't be decoded
+    pub fn decode_lossy(self, bytes: &[u8]) -> String {
+        self.decode(bytes)
+            .unwrap_or_else(|| String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    /// Encodes a command for the server, None if a character has no byte
+    pub fn encode(self, text: &str) -> Option<Vec<u8>> {
+        match self {
+            FilenameEncoding::Utf8 => Some(text.as_bytes().to_vec()),
+            FilenameEncoding::Latin1 => text.chars().map(|c| u8::try_from(c).ok()).collect(),
+            FilenameEncoding::Cp1251 => text
+                .chars()
+                .map(|c| match c {
+                    '\0'..='\u{7f}' => Some(c as u8),
+                    '\u{410}'..='\u{44f}' => Some((c as u32 - 0x410) as u8 + 0xc0),
+                    _ => CP1251_HIGH
+                        .iter()
+                        .position(|&high| high == c)
+                        .map(|index| index as u8 + 0x80),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for FilenameEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FilenameEncoding::Utf8 => "utf-8",
+            FilenameEncoding::Latin1 => "latin1",
+            FilenameEncoding::Cp1251 => "cp1251",
+        })
+    }
+}
+
+impl FromStr for FilenameEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(FilenameEncoding::Utf8),
+            "latin1" | "iso-8859-1" => Ok(FilenameEncoding::Latin1),
+            "cp1251" | "windows-1251" => Ok(FilenameEncoding::Cp1251),
+            _ => Err(format!("unknown encoding {}", s)),
+        }
+    }
+}
+
+/// Shows bytes that aren't valid UTF-8 as \xNN escapes, for the log
+pub fn escape_invalid(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    for chunk in bytes.utf8_chunks() {
+        escaped.push_str(chunk.valid());
+        for b in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_invalid, FilenameEncoding};
+
+    #[test]
+    fn test_round_trip() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for encoding in [FilenameEncoding::Latin1, FilenameEncoding::Cp1251] {
+            let name = encoding.decode(&bytes).unwrap();
+            assert_eq!(encoding.encode(&name).unwrap(), bytes);
+        }
+        let cp1251 = FilenameEncoding::Cp1251;
+        assert_eq!(
+            cp1251.decode(b"\xce\xf2\xf7\xb8\xf2.xml").unwrap(),
+            "Отчёт.xml"
+        );
+        assert_eq!(
+            cp1251.encode("Отчёт.xml").unwrap(),
+            b"\xce\xf2\xf7\xb8\xf2.xml"
+        );
+        assert_eq!(FilenameEncoding::Latin1.encode("Отчёт.xml"), None);
+        assert_eq!(FilenameEncoding::Utf8.decode(b"caf\xe9.xml"), None);
+    }
+
+    #[test]
+    fn test_escape_invalid() {
+        assert_eq!(escape_invalid(b"caf\xe9 \xc3\xa9.xml"), "caf\\xe9 é.xml");
+        assert_eq!(
+            "CP1251".parse::<FilenameEncoding>(),
+            Ok(FilenameEncoding::Cp1251)
+        );
+        assert!("koi8-r".parse::<FilenameEncoding>().is_err());
+    }
+}
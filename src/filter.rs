@@ -0,0 +1,367 @@
+//! Filter expressions selecting files by name, size and age
+//!
+//! A filter is a boolean expression over three properties of a listed file:
+//! `name` (string), `size` (bytes) and `age` (seconds), for example
+//! `name =~ "\.csv$" && size > 1024 && age > 300 && !(name =~ "^tmp")`.
+//!
+//! Names can be compared with `==`, `!=` and the regex operators `=~` and
+//! `!~`, numbers with `==`, `!=`, `<`, `<=`, `>` and `>=`. Comparisons are
+//! combined with `&&`, `||`, `!` and parentheses, `&&` binds tighter than
+//! `||`. Strings are written in double quotes, `\"` and `\\` are the only
+//! escapes, so regex escapes like `\.` are written as they are.
+
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+
+/// Properties of a listed file a filter is evaluated against
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub size: u64,
+    pub age: u64,
+}
+
+/// A parsed filter expression
+#[derive(Debug, Clone)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    NameMatches(Regex),
+    NameEquals(String),
+    Compare(Field, Op, u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Size,
+    Age,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(u64),
+    Sym(&'static str),
+}
+
+impl Filter {
+    /// Whether evaluating the filter needs the file size
+    pub fn uses_size(&self) -> bool {
+        self.expr.uses(Field::Size)
+    }
+
+    /// Evaluates the filter for a file
+    pub fn matches(&self, entry: &Entry) -> bool {
+        self.expr.eval(entry)
+    }
+}
+
+impl Expr {
+    fn uses(&self, field: Field) -> bool {
+        match self {
+            Expr::Or(a, b) | Expr::And(a, b) => a.uses(field) || b.uses(field),
+            Expr::Not(a) => a.uses(field),
+            Expr::NameMatches(_) | Expr::NameEquals(_) => false,
+            Expr::Compare(f, _, _) => *f == field,
+        }
+    }
+
+    fn eval(&self, entry: &Entry) -> bool {
+        match self {
+            Expr::Or(a, b) => a.eval(entry) || b.eval(entry),
+            Expr::And(a, b) => a.eval(entry) && b.eval(entry),
+            Expr::Not(a) => !a.eval(entry),
+            Expr::NameMatches(regex) => regex.is_match(entry.name),
+            Expr::NameEquals(name) => entry.name == name,
+            Expr::Compare(field, op, value) => {
+                let actual = match field {
+                    Field::Size => entry.size,
+                    Field::Age => entry.age,
+                };
+                match op {
+                    Op::Eq => actual == *value,
+                    Op::Ne => actual != *value,
+                    Op::Lt => actual < *value,
+                    Op::Le => actual <= *value,
+                    Op::Gt => actual > *value,
+                    Op::Ge => actual >= *value,
+                }
+            }
+        }
+    }
+}
+
+/// Two-character symbols come first so `<=` is not read as `<`
+const SYMBOLS: [&str; 14] = [
+    "=~", "!~", "==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "(", ")", "=",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c == '"' {
+            let mut value = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped @ ('"' | '\\'))) => value.push(escaped),
+                        Some((_, other)) => {
+                            value.push('\\');
+                            value.push(other);
+                        }
+                        None => return Err("unterminated string".to_string()),
+                    },
+                    Some((_, other)) => value.push(other),
+                    None => return Err("unterminated string".to_string()),
+                }
+            };
+            tokens.push(Token::Str(value));
+            rest = &rest[end..];
+        } else if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let number = rest[..end]
+                .parse()
+                .map_err(|_| format!("number too large: {}", &rest[..end]))?;
+            tokens.push(Token::Num(number));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| rest.starts_with(*s))
+                .ok_or_else(|| format!("unexpected character '{}'", c))?;
+            if *symbol == "=" {
+                return Err("use == to compare".to_string());
+            }
+            tokens.push(Token::Sym(symbol));
+            rest = &rest[symbol.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser over the token list
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Sym(s)) if *s == symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err("missing )".to_string());
+            }
+            return Ok(expr);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let field = match self.next() {
+            Some(Token::Ident(field)) => field,
+            Some(token) => return Err(format!("expected name, size or age, got {:?}", token)),
+            None => return Err("unexpected end of expression".to_string()),
+        };
+        let op = match self.next() {
+            Some(Token::Sym(op)) => op,
+            _ => return Err(format!("expected an operator after {}", field)),
+        };
+        let value = self.next();
+        match (field.as_str(), op, value) {
+            ("name", "=~" | "!~", Some(Token::Str(pattern))) => {
+                let regex = Regex::new(&pattern).map_err(|e| e.to_string())?;
+                let expr = Expr::NameMatches(regex);
+                Ok(if op == "!~" {
+                    Expr::Not(Box::new(expr))
+                } else {
+                    expr
+                })
+            }
+            ("name", "==" | "!=", Some(Token::Str(name))) => {
+                let expr = Expr::NameEquals(name);
+                Ok(if op == "!=" {
+                    Expr::Not(Box::new(expr))
+                } else {
+                    expr
+                })
+            }
+            ("name", _, _) => Err(format!("name can't be compared with {}", op)),
+            ("size" | "age", _, Some(Token::Num(value))) => {
+                let kind = if field == "size" {
+                    Field::Size
+                } else {
+                    Field::Age
+                };
+                let op = match op {
+                    "==" => Op::Eq,
+                    "!=" => Op::Ne,
+                    "<" => Op::Lt,
+                    "<=" => Op::Le,
+                    ">" => Op::Gt,
+                    ">=" => Op::Ge,
+                    _ => return Err(format!("{} can't be compared with {}", field, op)),
+                };
+                Ok(Expr::Compare(kind, op, value))
+            }
+            ("size" | "age", _, _) => Err(format!("{} must be compared with a number", field)),
+            _ => Err(format!("unknown field: {}", field)),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Filter, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {:?}", token));
+        }
+        Ok(Filter {
+            source: source.to_string(),
+            expr,
+        })
+    }
+}
+
+/// Filters are equal when written the same way, regexes can't be compared
+impl PartialEq for Filter {
+    fn eq(&self, other: &Filter) -> bool {
+        self.source == other.source
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, Filter};
+
+    fn matches(filter: &str, name: &str, size: u64, age: u64) -> bool {
+        filter
+            .parse::<Filter>()
+            .unwrap()
+            .matches(&Entry { name, size, age })
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let filter = r#"name =~ "\.csv$" && size > 1024 && age > 300 && !(name =~ "^tmp")"#;
+        assert!(matches(filter, "a.csv", 2048, 600));
+        assert!(!matches(filter, "a.csv", 512, 600));
+        assert!(!matches(filter, "a.csv", 2048, 60));
+        assert!(!matches(filter, "tmp.csv", 2048, 600));
+        assert!(!matches(filter, "a.xml", 2048, 600));
+
+        // && binds tighter than ||
+        let filter = r#"name == "a" || name == "b" && size == 0"#;
+        assert!(matches(filter, "a", 1, 0));
+        assert!(!matches(filter, "b", 1, 0));
+        assert!(matches(filter, "b", 0, 0));
+
+        assert!(matches(r#"name !~ "^\." && age <= 10"#, "x", 0, 10));
+        assert!(matches(r#"name != "say \"hi\"""#, "x", 0, 0));
+        assert!(!matches(r#"name != "say \"hi\"""#, "say \"hi\"", 0, 0));
+    }
+
+    #[test]
+    fn test_filter_errors() {
+        for filter in [
+            "",
+            "size > ",
+            "size = 1",
+            "size > \"1\"",
+            "name > \"a\"",
+            "name =~ \"(\"",
+            "color == 1",
+            "(age > 1",
+            "age > 1 age > 2",
+            "name == \"a",
+        ] {
+            assert!(filter.parse::<Filter>().is_err(), "{}", filter);
+        }
+        assert!(!"age > 1".parse::<Filter>().unwrap().uses_size());
+        assert!("!(age > 1 || size < 2)"
+            .parse::<Filter>()
+            .unwrap()
+            .uses_size());
+    }
+}
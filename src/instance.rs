@@ -0,0 +1,521 @@
+//! Control socket of a daemon, see the status subcommand
+//!
+//! A daemon listens on a Unix socket for one command per connection, a line
+//! of text, and answers it with a reply before closing the connection:
+//!
+//! * `STATUS` - JSON describing the daemon, see DaemonStatus::to_json
+//! * `SHUTDOWN` - stop like on SIGTERM, the reply is `OK`
+//! * `PAUSE` - start no new file until `RESUME`, the reply is `OK`
+//! * `RESUME` - go on after `PAUSE`, the reply is `OK`
+//!
+//! Anything else gets `ERROR` and why. Only the user running the daemon can
+//! connect to the socket. A daemon keeps its pid file locked while it runs, so
+//! a second one of the same instance refuses to start, and a socket left by a
+//! daemon that died is replaced.
+//!
+//! The socket and the pid file of the daemon are named after its instance, so
+//! daemons for separate config files can run side by side, see Instance. Their
+//! directory must belong to the user and be writable by no one else.
+//!
+//! Other systems than Unix have no control socket, a daemon runs without one.
+
+use crate::report::json_string;
+use std::env;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(unix)]
+use std::{
+    fs::{DirBuilder, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    os::unix::io::AsRawFd,
+    os::unix::net::{UnixListener, UnixStream},
+    thread,
+    time::Duration,
+};
+
+/// Name of the instance unless --instance-name is given
+pub const DEFAULT_INSTANCE: &str = "iftpfm2";
+
+/// Where the files of a daemon go, see --runtime-dir and --instance-name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instance {
+    dir: PathBuf,
+    name: String,
+}
+
+impl Instance {
+    /// Returns the instance named name in dir
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Where the files go, $XDG_RUNTIME_DIR or else a directory of
+    ///   the user in /tmp by default, see listen
+    /// * `name` - Name of the files, DEFAULT_INSTANCE by default
+    pub fn new(dir: Option<&str>, name: Option<&str>) -> Instance {
+        let dir = match dir {
+            Some(dir) => PathBuf::from(dir),
+            None => env::var_os("XDG_RUNTIME_DIR")
+                .filter(|dir| !dir.is_empty())
+                .map_or_else(private_temp_dir, PathBuf::from),
+        };
+        Instance {
+            dir,
+            name: name.unwrap_or(DEFAULT_INSTANCE).to_string(),
+        }
+    }
+
+    /// Where the daemon listens for commands
+    pub fn socket_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.sock", self.name))
+    }
+
+    /// Where the daemon writes its process id, for signals like SIGHUP
+    pub fn pid_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.pid", self.name))
+    }
+}
+
+/// The directory of the user in /tmp, see Instance::new
+#[cfg(unix)]
+fn private_temp_dir() -> PathBuf {
+    let uid = unsafe { libc::geteuid() };
+    env::temp_dir().join(format!("{}-{}", DEFAULT_INSTANCE, uid))
+}
+
+#[cfg(not(unix))]
+fn private_temp_dir() -> PathBuf {
+    env::temp_dir().join(DEFAULT_INSTANCE)
+}
+
+/// How long the status subcommand waits for the reply of the daemon
+#[cfg(unix)]
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Set by PAUSE and cleared by RESUME, see paused
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the daemon was paused, the file being transferred is finished but
+/// no other one is started
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// What STATUS reports, kept up to date by the daemon
+#[derive(Debug)]
+pub struct DaemonStatus {
+    pub started: SystemTime,
+    /// The jobs of the config file in use
+    pub jobs: Vec<JobStatus>,
+    /// Whether jobs are being run, otherwise the daemon waits for the next one
+    pub running: bool,
+    /// Files being transferred
+    pub in_flight: Vec<FileInFlight>,
+    /// The run in progress, or the last one while waiting
+    pub run: RunTotals,
+    /// Files delivered since the daemon started
+    pub transferred: u64,
+}
+
+/// A job of a daemon, see DaemonStatus::jobs
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobStatus {
+    pub name: Option<String>,
+    /// SOURCE and TARGET of the job, see crate::job_location
+    pub location: String,
+    pub next_run: SystemTime,
+}
+
+/// A file being transferred, see DaemonStatus::in_flight
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInFlight {
+    /// Name of the file on the SOURCE
+    pub filename: String,
+    /// ftp:// URL of the file on the TARGET
+    pub target: String,
+    /// Bytes read from the SOURCE so far
+    pub bytes: u64,
+    /// Size announced by the SOURCE, if any
+    pub size: Option<u64>,
+    pub started: SystemTime,
+}
+
+/// Files of one run of a daemon, see DaemonStatus::run
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunTotals {
+    /// None before the first run
+    pub started: Option<SystemTime>,
+    pub transferred: u64,
+    pub failed: u64,
+    /// Bytes of the delivered files
+    pub bytes: u64,
+}
+
+impl DaemonStatus {
+    pub fn new() -> DaemonStatus {
+        DaemonStatus {
+            started: SystemTime::now(),
+            jobs: Vec::new(),
+            running: false,
+            in_flight: Vec::new(),
+            run: RunTotals::default(),
+            transferred: 0,
+        }
+    }
+
+    /// Formats the status as the reply to STATUS
+    ///
+    /// Times are seconds since the epoch, like in the status files.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The time uptime is counted to
+    pub fn to_json(&self, now: SystemTime) -> String {
+        let seconds = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        };
+        let mut json = String::new();
+        json.push_str("{\n");
+        json.push_str(&format!("  \"pid\": {},\n", process::id()));
+        json.push_str(&format!(
+            "  \"version\": {},\n",
+            json_string(crate::PROGRAM_VERSION)
+        ));
+        let uptime = now.duration_since(self.started).unwrap_or_default();
+        json.push_str(&format!("  \"uptime\": {},\n", uptime.as_secs()));
+        json.push_str(&format!("  \"running\": {},\n", self.running));
+        json.push_str(&format!("  \"paused\": {},\n", paused()));
+        json.push_str("  \"jobs\": [");
+        for (index, job) in self.jobs.iter().enumerate() {
+            json.push_str(if index == 0 { "\n" } else { ",\n" });
+            json.push_str(&format!(
+                "    {{\"job\": {}, \"name\": {}, \"location\": {}, \"next_run\": {}}}",
+                index + 1,
+                job.name.as_deref().map_or("null".to_string(), json_string),
+                json_string(&job.location),
+                seconds(job.next_run)
+            ));
+        }
+        json.push_str(if self.jobs.is_empty() {
+            "],\n"
+        } else {
+            "\n  ],\n"
+        });
+        json.push_str("  \"in_flight\": [");
+        for (index, file) in self.in_flight.iter().enumerate() {
+            json.push_str(if index == 0 { "\n" } else { ",\n" });
+            json.push_str(&format!(
+                "    {{\"filename\": {}, \"target\": {}, \"bytes\": {}, \"size\": {}, \"started\": {}}}",
+                json_string(&file.filename),
+                json_string(&file.target),
+                file.bytes,
+                file.size.map_or("null".to_string(), |size| size.to_string()),
+                seconds(file.started)
+            ));
+        }
+        json.push_str(if self.in_flight.is_empty() {
+            "],\n"
+        } else {
+            "\n  ],\n"
+        });
+        json.push_str(&format!(
+            "  \"run\": {{\"started\": {}, \"transferred\": {}, \"failed\": {}, \"bytes\": {}}},\n",
+            self.run
+                .started
+                .map_or("null".to_string(), |started| seconds(started).to_string()),
+            self.run.transferred,
+            self.run.failed,
+            self.run.bytes
+        ));
+        json.push_str(&format!("  \"transferred\": {}\n", self.transferred));
+        json.push_str("}\n");
+        json
+    }
+}
+
+impl Default for DaemonStatus {
+    fn default() -> Self {
+        DaemonStatus::new()
+    }
+}
+
+/// The socket and pid file of a running daemon, removed when dropped
+#[derive(Debug)]
+pub struct ControlSocket {
+    instance: Instance,
+    /// The pid file, locked as long as it is open
+    #[cfg(unix)]
+    pid_file: File,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.instance.socket_path());
+        let _ = fs::remove_file(self.instance.pid_path());
+    }
+}
+
+/// Makes sure dir exists, belongs to the user and only the user can write to it
+///
+/// A missing dir is created with mode 0700. Otherwise another user could
+/// replace the socket or the pid file, or put a symlink in their place.
+#[cfg(unix)]
+fn check_dir(dir: &Path) -> io::Result<()> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e),
+        _ => (),
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    let uid = unsafe { libc::geteuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o022 != 0 {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "{} is not a directory of uid {} writable only by it",
+                dir.display(),
+                uid
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Opens and locks the pid file of the instance, AddrInUse if another daemon holds it
+#[cfg(unix)]
+fn lock_pid_file(instance: &Instance) -> io::Result<File> {
+    let path = instance.pid_path();
+    loop {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = Error::last_os_error();
+            if e.kind() != ErrorKind::WouldBlock {
+                return Err(e);
+            }
+            let pid = fs::read_to_string(&path).unwrap_or_default();
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                format!(
+                    "another daemon with pid {} holds {}",
+                    pid.trim(),
+                    path.display()
+                ),
+            ));
+        }
+        // A daemon stopping meanwhile removes the file it had locked
+        let locked = file.metadata()?;
+        match fs::symlink_metadata(&path) {
+            Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {
+                return Ok(file)
+            }
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Starts answering commands on the control socket in a thread
+///
+/// The pid file is locked first and gets the process id once the socket is
+/// listening.
+///
+/// # Arguments
+///
+/// * `instance` - Where the socket and the pid file go
+/// * `status` - What STATUS reports
+/// * `shutdown` - Called for SHUTDOWN
+///
+/// # Returns
+///
+/// * The socket, AddrInUse if another daemon of the instance is running
+#[cfg(unix)]
+pub fn listen(
+    instance: &Instance,
+    status: Arc<Mutex<DaemonStatus>>,
+    shutdown: fn(),
+) -> io::Result<ControlSocket> {
+    check_dir(&instance.dir)?;
+    let pid_file = lock_pid_file(instance)?;
+    let path = &instance.socket_path();
+    // With the lock held a socket can only be left by a daemon that was killed
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    let listener = UnixListener::bind(path)?;
+    let mut socket = ControlSocket {
+        instance: instance.clone(),
+        pid_file,
+    };
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    socket.pid_file.set_len(0)?;
+    socket.pid_file.rewind()?;
+    writeln!(socket.pid_file, "{}", process::id())?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // A client that never sends its command must not block the others
+            let _ = stream.set_read_timeout(Some(REPLY_TIMEOUT));
+            let _ = answer(stream, &status, shutdown);
+        }
+    });
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+pub fn listen(
+    _instance: &Instance,
+    _status: Arc<Mutex<DaemonStatus>>,
+    _shutdown: fn(),
+) -> io::Result<ControlSocket> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "control sockets need Unix domain sockets",
+    ))
+}
+
+#[cfg(unix)]
+fn answer(stream: UnixStream, status: &Mutex<DaemonStatus>, shutdown: fn()) -> io::Result<()> {
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+    let reply = match command.trim().to_ascii_uppercase().as_str() {
+        "STATUS" => status.lock().unwrap().to_json(SystemTime::now()),
+        "SHUTDOWN" => {
+            shutdown();
+            "OK\n".to_string()
+        }
+        "PAUSE" => {
+            PAUSED.store(true, Ordering::SeqCst);
+            "OK\n".to_string()
+        }
+        "RESUME" => {
+            PAUSED.store(false, Ordering::SeqCst);
+            "OK\n".to_string()
+        }
+        other => format!("ERROR unknown command {}\n", other),
+    };
+    (&stream).write_all(reply.as_bytes())
+}
+
+/// Sends a command to the daemon listening on path and returns its reply
+#[cfg(unix)]
+pub fn send_command(path: &Path, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+#[cfg(not(unix))]
+pub fn send_command(_path: &Path, _command: &str) -> io::Result<String> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "control sockets need Unix domain sockets",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DaemonStatus, FileInFlight, JobStatus};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_status_json() {
+        let mut status = DaemonStatus::new();
+        status.started = UNIX_EPOCH + Duration::from_secs(1000);
+        let json = status.to_json(UNIX_EPOCH + Duration::from_secs(1060));
+        assert!(json.contains("  \"uptime\": 60,\n  \"running\": false,\n"));
+        // paused is left out, test_control_socket may be pausing meanwhile
+        assert!(json.contains(
+            "  \"jobs\": [],\n  \"in_flight\": [],\n  \"run\": {\"started\": null, \"transferred\": 0, \"failed\": 0, \"bytes\": 0},\n"
+        ));
+        status.jobs.push(JobStatus {
+            name: Some("orders".to_string()),
+            location: "ftp://a:21/in -> ftp://b:21/out".to_string(),
+            next_run: UNIX_EPOCH + Duration::from_secs(1100),
+        });
+        status.in_flight.push(FileInFlight {
+            filename: "a \"1\".xml".to_string(),
+            target: "ftp://b:21/out/a \"1\".xml".to_string(),
+            bytes: 10,
+            size: None,
+            started: UNIX_EPOCH + Duration::from_secs(1050),
+        });
+        let json = status.to_json(UNIX_EPOCH + Duration::from_secs(1060));
+        assert!(json.contains(
+            "  \"jobs\": [\n    {\"job\": 1, \"name\": \"orders\", \"location\": \"ftp://a:21/in -> ftp://b:21/out\", \"next_run\": 1100}\n  ],\n"
+        ));
+        assert!(json.contains(
+            "{\"filename\": \"a \\\"1\\\".xml\", \"target\": \"ftp://b:21/out/a \\\"1\\\".xml\", \"bytes\": 10, \"size\": null, \"started\": 1050}"
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_control_socket() {
+        use super::{listen, paused, send_command, Instance};
+        use std::os::unix::fs::PermissionsExt;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::{Arc, Mutex};
+        use tempfile::tempdir;
+        static STOPPED: AtomicBool = AtomicBool::new(false);
+        let dir = tempdir().unwrap();
+        let instance = Instance::new(dir.path().to_str(), Some("test"));
+        let path = dir.path().join("test.sock");
+        assert_eq!(instance.socket_path(), path);
+        let status = Arc::new(Mutex::new(DaemonStatus::new()));
+        let socket = listen(&instance, status.clone(), || {
+            STOPPED.store(true, Ordering::SeqCst)
+        })
+        .unwrap();
+        let pid = std::fs::read_to_string(dir.path().join("test.pid")).unwrap();
+        assert_eq!(pid, format!("{}\n", std::process::id()));
+        // Other instances don't get in the way
+        let other = Instance::new(dir.path().to_str(), Some("other"));
+        drop(listen(&other, status.clone(), || ()).unwrap());
+        status.lock().unwrap().transferred = 7;
+        let reply = send_command(&path, "status").unwrap();
+        assert!(reply.contains("\"transferred\": 7\n"));
+        assert_eq!(
+            send_command(&path, "FOO").unwrap(),
+            "ERROR unknown command FOO\n"
+        );
+        assert!(listen(&instance, status.clone(), || ()).is_err());
+        assert_eq!(send_command(&path, "PAUSE").unwrap(), "OK\n");
+        assert!(paused());
+        assert!(send_command(&path, "STATUS")
+            .unwrap()
+            .contains("\"paused\": true,\n"));
+        assert_eq!(send_command(&path, "RESUME").unwrap(), "OK\n");
+        assert!(!paused());
+        assert_eq!(send_command(&path, "SHUTDOWN").unwrap(), "OK\n");
+        assert!(STOPPED.load(Ordering::SeqCst));
+        drop(socket);
+        assert!(!path.exists());
+        assert!(!dir.path().join("test.pid").exists());
+        // Nor do other users, who could swap the files
+        let shared = dir.path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let instance = Instance::new(shared.to_str(), None);
+        assert!(listen(&instance, status.clone(), || ()).is_err());
+        let private = dir.path().join("private");
+        let instance = Instance::new(private.to_str(), None);
+        drop(listen(&instance, status, || ()).unwrap());
+        let mode = std::fs::metadata(&private).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+}
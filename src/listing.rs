@@ -0,0 +1,207 @@
+//! Directory listings carrying file sizes and modification times
+//!
+//! NLST only returns names, so the size and modification time of every file
+//! cost one SIZE and one MDTM round trip each. Servers supporting MLSD
+//! (RFC 3659) return all of them in a single listing. Servers supporting
+//! neither MDTM nor SIZE still have LIST, whose human readable output is
+//! parsed as a last resort.
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// A file or directory from a detailed listing
+#[derive(Debug, PartialEq)]
+pub struct ListEntry {
+    pub name: String,
+    pub is_file: bool,
+    pub size: Option<u64>,
+    /// Modification time as reported by the server, normally UTC
+    pub modified: Option<NaiveDateTime>,
+}
+
+/// Parses a line of an MLSD listing, like `type=file;size=12;modify=20240101120000; a.xml`
+///
+/// Facts are case insensitive and may come in any order, unknown ones are
+/// ignored. An entry without a type fact is taken to be a file.
+pub fn parse_mlsd_line(line: &str) -> Option<ListEntry> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let (facts, name) = line.split_once(' ')?;
+    if name.is_empty() {
+        return None;
+    }
+    let mut entry = ListEntry {
+        name: name.to_string(),
+        is_file: true,
+        size: None,
+        modified: None,
+    };
+    for fact in facts.split(';').filter(|fact| !fact.is_empty()) {
+        let (key, value) = fact.split_once('=')?;
+        match key.to_ascii_lowercase().as_str() {
+            "type" => entry.is_file = value.eq_ignore_ascii_case("file"),
+            "size" => entry.size = value.parse().ok(),
+            // Fractions of a second may follow the 14 digits
+            "modify" => {
+                entry.modified = value
+                    .get(..14)
+                    .and_then(|v| NaiveDateTime::parse_from_str(v, "%Y%m%d%H%M%S").ok())
+            }
+            _ => (),
+        }
+    }
+    Some(entry)
+}
+
+// drwxr-xr-x 2 owner group 4096 Jan 02 12:34 name, the group may be missing
+// and older files have a year instead of the time
+static UNIX_LIST_LINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^([-dlbcps])\S{9}\S*\s+\d+\s+(?:\S+\s+){1,2}(\d+)\s+([A-Za-z]{3})\s+(\d{1,2})\s+(\d{1,2}:\d{2}|\d{4})\s(.+)$",
+    )
+    .unwrap()
+});
+
+// 01-02-24  03:04PM       1234 name, or <DIR> instead of the size
+static DOS_LIST_LINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^(\d{2})-(\d{2})-(\d{2}|\d{4})\s+(\d{1,2}):(\d{2})([AaPp][Mm])?\s+(<DIR>|\d+)\s+(.+)$",
+    )
+    .unwrap()
+});
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// Parses a line of a LIST listing in unix `ls -l` or DOS/IIS format
+///
+/// Times in LIST output have minute precision at best. Unix listings leave
+/// out the year for recent files, it is taken to be the one that puts the
+/// date not more than a day after `today`.
+pub fn parse_list_line(line: &str, today: NaiveDate) -> Option<ListEntry> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    if let Some(caps) = UNIX_LIST_LINE.captures(line) {
+        let month = MONTHS
+            .iter()
+            .position(|m| m.eq_ignore_ascii_case(&caps[3]))? as u32
+            + 1;
+        let day: u32 = caps[4].parse().ok()?;
+        let (year, hour, minute) = match caps[5].split_once(':') {
+            Some((hour, minute)) => {
+                let date = NaiveDate::from_ymd_opt(today.year(), month, day)?;
+                let year = if date > today.succ_opt()? {
+                    today.year() - 1
+                } else {
+                    today.year()
+                };
+                (year, hour.parse().ok()?, minute.parse().ok()?)
+            }
+            None => (caps[5].parse().ok()?, 0, 0),
+        };
+        let kind = &caps[1];
+        // Symlinks are listed as "name -> target"
+        let name = match (kind, caps[6].split_once(" -> ")) {
+            ("l", Some((name, _))) => name,
+            _ => &caps[6],
+        };
+        return Some(ListEntry {
+            name: name.to_string(),
+            is_file: kind == "-" || kind == "l",
+            size: caps[2].parse().ok(),
+            modified: NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(hour, minute, 0),
+        });
+    }
+    if let Some(caps) = DOS_LIST_LINE.captures(line) {
+        let month: u32 = caps[1].parse().ok()?;
+        let day: u32 = caps[2].parse().ok()?;
+        let year: i32 = match caps[3].parse().ok()? {
+            year @ 0..=69 => 2000 + year,
+            year @ 70..=99 => 1900 + year,
+            year => year,
+        };
+        let mut hour: u32 = caps[4].parse().ok()?;
+        match caps.get(6).map(|m| m.as_str().to_ascii_uppercase()) {
+            Some(ampm) if ampm == "PM" && hour < 12 => hour += 12,
+            Some(ampm) if ampm == "AM" && hour == 12 => hour = 0,
+            _ => (),
+        }
+        let is_file = &caps[7] != "<DIR>";
+        return Some(ListEntry {
+            name: caps[8].to_string(),
+            is_file,
+            size: if is_file { caps[7].parse().ok() } else { None },
+            modified: NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(
+                hour,
+                caps[5].parse().ok()?,
+                0,
+            ),
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_list_line, parse_mlsd_line};
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_parse_mlsd_line() {
+        let entry =
+            parse_mlsd_line("type=file;size=1024;modify=20240102030405.123; a b.xml\r\n").unwrap();
+        assert_eq!(entry.name, "a b.xml");
+        assert!(entry.is_file);
+        assert_eq!(entry.size, Some(1024));
+        assert_eq!(
+            entry.modified,
+            NaiveDate::from_ymd_opt(2024, 1, 2).and_then(|d| d.and_hms_opt(3, 4, 5))
+        );
+
+        let entry = parse_mlsd_line("Type=dir;Modify=20240102030405; sub").unwrap();
+        assert!(!entry.is_file);
+        assert_eq!(entry.size, None);
+
+        assert!(parse_mlsd_line("type=cdir; .").map(|e| !e.is_file).unwrap());
+        assert!(parse_mlsd_line("garbage").is_none());
+    }
+
+    #[test]
+    fn test_parse_list_line() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+        let at = |y, m, d, h, min| {
+            NaiveDate::from_ymd_opt(y, m, d).and_then(|d| d.and_hms_opt(h, min, 0))
+        };
+
+        let entry =
+            parse_list_line("-rw-r--r--   1 ftp  ftp   1234 Mar 09 12:34 a b.xml", today).unwrap();
+        assert_eq!(entry.name, "a b.xml");
+        assert!(entry.is_file);
+        assert_eq!(entry.size, Some(1234));
+        assert_eq!(entry.modified, at(2024, 3, 9, 12, 34));
+
+        // A date after today without a year is from last year
+        let entry = parse_list_line("-rw-r--r-- 1 ftp 5 Dec 24 08:00 x.xml", today).unwrap();
+        assert_eq!(entry.modified, at(2023, 12, 24, 8, 0));
+
+        let entry = parse_list_line("-rw-r--r-- 1 ftp ftp 5 Jan  2  2021 old.xml", today).unwrap();
+        assert_eq!(entry.modified, at(2021, 1, 2, 0, 0));
+
+        let entry = parse_list_line("drwxr-xr-x 2 ftp ftp 4096 Mar 01 10:00 sub", today).unwrap();
+        assert!(!entry.is_file);
+        let entry =
+            parse_list_line("lrwxrwxrwx 1 ftp ftp 9 Mar 01 10:00 l.xml -> a.xml", today).unwrap();
+        assert_eq!(entry.name, "l.xml");
+
+        let entry =
+            parse_list_line("03-09-24  01:05PM                 1234 dos file.xml", today).unwrap();
+        assert_eq!(entry.name, "dos file.xml");
+        assert_eq!(entry.size, Some(1234));
+        assert_eq!(entry.modified, at(2024, 3, 9, 13, 5));
+        let entry = parse_list_line("12-31-1999  12:00AM       <DIR>          old", today).unwrap();
+        assert!(!entry.is_file);
+        assert_eq!(entry.modified, at(1999, 12, 31, 0, 0));
+
+        assert!(parse_list_line("total 12", today).is_none());
+    }
+}
@@ -0,0 +1,319 @@
+//! Machine-readable summary of a run, see the --report option
+//!
+//! Every job of the run gets a JobReport with the files whose transfer it
+//! attempted. The report is written as JSON, or as CSV with one row per file
+//! (and one per job without files) when the path ends in `.csv`.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Outcome of a file whose transfer was attempted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    /// Delivered to the TARGET
+    Transferred,
+    /// Not delivered, the next run tries again
+    Failed,
+    /// Retrieved but not delivered again, its content did not change (skip_unchanged)
+    Unchanged,
+    /// Cut short by job_timeout, the next run tries again
+    Deferred,
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FileStatus::Transferred => "transferred",
+            FileStatus::Failed => "failed",
+            FileStatus::Unchanged => "unchanged",
+            FileStatus::Deferred => "deferred",
+        })
+    }
+}
+
+/// What happened to one file of a job
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileReport {
+    /// Name of the file on the SOURCE
+    pub filename: String,
+    /// ftp:// URL of the file on the TARGET
+    pub target: String,
+    pub status: FileStatus,
+    /// Size of the file, if known
+    pub bytes: Option<u64>,
+    /// Time from the start of the transfer until it was delivered or given up
+    pub duration: Duration,
+    /// Why the file was not delivered
+    pub error: Option<String>,
+}
+
+/// What a job that could be processed did, file by file
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TransferReport {
+    /// Files whose transfer was attempted, in the order they were done
+    pub files: Vec<FileReport>,
+}
+
+impl TransferReport {
+    /// Number of files delivered
+    pub fn transferred(&self) -> i32 {
+        self.count(FileStatus::Transferred)
+    }
+
+    /// Number of files whose transfer was attempted but not completed
+    pub fn failed(&self) -> i32 {
+        self.count(FileStatus::Failed)
+    }
+
+    fn count(&self, status: FileStatus) -> i32 {
+        self.files
+            .iter()
+            .filter(|file| file.status == status)
+            .count() as i32
+    }
+}
+
+/// Entry of one job in the report of a run
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobReport {
+    /// Number of the job in the config file, counting from 1
+    pub job: usize,
+    /// SOURCE and TARGET of the job, see crate::job_location
+    pub location: String,
+    /// "ok", "unreachable", "busy", "failed" or "skipped"
+    pub status: &'static str,
+    /// Why the job could not be processed or was skipped
+    pub error: Option<String>,
+    pub report: TransferReport,
+}
+
+/// Writes the report of a run to a temporary file and renames it into place
+///
+/// # Arguments
+///
+/// * `path` - Where the report goes, CSV if it ends in .csv and JSON otherwise
+/// * `started` - When the run started, in RFC 3339 format
+/// * `jobs` - The jobs of the run
+pub fn write_report(path: &Path, started: &str, jobs: &[JobReport]) -> io::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    if path.extension().is_some_and(|ext| ext == "csv") {
+        write_csv(&mut file, jobs)?;
+    } else {
+        write_json(&mut file, started, jobs)?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+fn write_csv<W: Write>(out: W, jobs: &[JobReport]) -> io::Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "job",
+        "location",
+        "job_status",
+        "filename",
+        "target",
+        "status",
+        "bytes",
+        "seconds",
+        "error",
+    ])?;
+    for job in jobs {
+        let number = job.job.to_string();
+        if job.report.files.is_empty() {
+            let error = job.error.as_deref().unwrap_or("");
+            writer.write_record([
+                &number,
+                &job.location,
+                job.status,
+                "",
+                "",
+                "",
+                "",
+                "",
+                error,
+            ])?;
+        }
+        for file in &job.report.files {
+            writer.write_record([
+                number.as_str(),
+                &job.location,
+                job.status,
+                &file.filename,
+                &file.target,
+                &file.status.to_string(),
+                &file.bytes.map_or(String::new(), |bytes| bytes.to_string()),
+                &format!("{:.3}", file.duration.as_secs_f64()),
+                file.error.as_deref().unwrap_or(""),
+            ])?;
+        }
+    }
+    writer.flush()
+}
+
+fn write_json<W: Write>(mut out: W, started: &str, jobs: &[JobReport]) -> io::Result<()> {
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"started\": {},", json_string(started))?;
+    writeln!(out, "  \"jobs\": [")?;
+    for (index, job) in jobs.iter().enumerate() {
+        writeln!(out, "    {{")?;
+        writeln!(out, "      \"job\": {},", job.job)?;
+        writeln!(out, "      \"location\": {},", json_string(&job.location))?;
+        writeln!(out, "      \"status\": {},", json_string(job.status))?;
+        writeln!(
+            out,
+            "      \"error\": {},",
+            json_option(job.error.as_deref())
+        )?;
+        writeln!(out, "      \"transferred\": {},", job.report.transferred())?;
+        writeln!(out, "      \"failed\": {},", job.report.failed())?;
+        if job.report.files.is_empty() {
+            writeln!(out, "      \"files\": []")?;
+        } else {
+            writeln!(out, "      \"files\": [")?;
+        }
+        for (index, file) in job.report.files.iter().enumerate() {
+            let bytes = file
+                .bytes
+                .map_or("null".to_string(), |bytes| bytes.to_string());
+            write!(
+                out,
+                "        {{\"filename\": {}, \"target\": {}, \"status\": \"{}\", \"bytes\": {}, \"seconds\": {:.3}, \"error\": {}}}",
+                json_string(&file.filename),
+                json_string(&file.target),
+                file.status,
+                bytes,
+                file.duration.as_secs_f64(),
+                json_option(file.error.as_deref())
+            )?;
+            writeln!(out, "{}", separator(index, job.report.files.len()))?;
+        }
+        if !job.report.files.is_empty() {
+            writeln!(out, "      ]")?;
+        }
+        writeln!(out, "    }}{}", separator(index, jobs.len()))?;
+    }
+    writeln!(out, "  ]")?;
+    writeln!(out, "}}")
+}
+
+fn separator(index: usize, len: usize) -> &'static str {
+    if index + 1 < len {
+        ","
+    } else {
+        ""
+    }
+}
+
+fn json_option(value: Option<&str>) -> String {
+    value.map_or("null".to_string(), json_string)
+}
+
+/// Quotes a string for JSON
+pub fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_string, write_report, FileReport, FileStatus, JobReport, TransferReport};
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn jobs() -> Vec<JobReport> {
+        let files = vec![
+            FileReport {
+                filename: "a.xml".to_string(),
+                target: "ftp://10.0.0.2:21/in/a.xml".to_string(),
+                status: FileStatus::Transferred,
+                bytes: Some(1024),
+                duration: Duration::from_millis(1500),
+                error: None,
+            },
+            FileReport {
+                filename: "b \"1\".xml".to_string(),
+                target: "ftp://10.0.0.2:21/in/b \"1\".xml".to_string(),
+                status: FileStatus::Failed,
+                bytes: None,
+                duration: Duration::from_millis(20),
+                error: Some("Error transferring file b \"1\".xml".to_string()),
+            },
+        ];
+        vec![
+            JobReport {
+                job: 1,
+                location: "ftp://10.0.0.1:21/out -> ftp://10.0.0.2:21/in".to_string(),
+                status: "ok",
+                error: None,
+                report: TransferReport { files },
+            },
+            JobReport {
+                job: 2,
+                location: "ftp://10.0.0.3:21/out -> ftp://10.0.0.2:21/in".to_string(),
+                status: "unreachable",
+                error: Some("connection refused".to_string()),
+                report: TransferReport::default(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_transfer_report_counts() {
+        let report = &jobs()[0].report;
+        assert_eq!(report.transferred(), 1);
+        assert_eq!(report.failed(), 1);
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+    }
+
+    #[test]
+    fn test_write_report() {
+        let dir = tempdir().unwrap();
+        let json = dir.path().join("report.json");
+        write_report(&json, "2024-01-31T10:00:00+01:00", &jobs()).unwrap();
+        let contents = std::fs::read_to_string(&json).unwrap();
+        assert!(contents.contains("\"started\": \"2024-01-31T10:00:00+01:00\","));
+        assert!(contents.contains("{\"filename\": \"a.xml\", \"target\": \"ftp://10.0.0.2:21/in/a.xml\", \"status\": \"transferred\", \"bytes\": 1024, \"seconds\": 1.500, \"error\": null},\n"));
+        assert!(contents.contains("\"bytes\": null, \"seconds\": 0.020, \"error\": \"Error transferring file b \\\"1\\\".xml\"}\n"));
+        assert!(contents
+            .contains("\"status\": \"unreachable\",\n      \"error\": \"connection refused\","));
+        assert!(contents.contains("\"failed\": 0,\n      \"files\": []\n    }\n  ]\n}\n"));
+
+        let csv = dir.path().join("report.csv");
+        write_report(&csv, "2024-01-31T10:00:00+01:00", &jobs()).unwrap();
+        let contents = std::fs::read_to_string(&csv).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            "job,location,job_status,filename,target,status,bytes,seconds,error"
+        );
+        assert_eq!(lines[1], "1,ftp://10.0.0.1:21/out -> ftp://10.0.0.2:21/in,ok,a.xml,ftp://10.0.0.2:21/in/a.xml,transferred,1024,1.500,");
+        assert_eq!(
+            lines[3],
+            "2,ftp://10.0.0.3:21/out -> ftp://10.0.0.2:21/in,unreachable,,,,,,connection refused"
+        );
+    }
+}
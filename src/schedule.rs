@@ -0,0 +1,231 @@
+//! Cron-like schedules of jobs in daemon mode, see the schedule option
+//!
+//! A schedule has the five fields of a crontab line: minute (0-59), hour
+//! (0-23), day of month (1-31), month (1-12 or JAN-DEC) and day of week (0-7
+//! or SUN-SAT, both 0 and 7 are Sunday). A field is `*`, a number, a range
+//! like `8-18` or a list of them, and `*` and ranges can take a step like
+//! `*/5`. Fields of config lines are separated by commas, so lists are written
+//! with `;`, like `0;30 8-18 * * MON-FRI`. Like in cron, when both the day of
+//! month and the day of week are restricted, a day matching either one does.
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use std::fmt;
+use std::str::FromStr;
+
+const MONTHS: [&str; 12] = [
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+const WEEKDAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+/// How far ahead the next run is looked for, longer than any leap year cycle
+const SEARCH_DAYS: i64 = 5 * 366;
+
+/// When a job runs in daemon mode, one bit per value of each field
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month or the day of week is `*`, see matches_day
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// Returns the first time after `after` the job is due, to the minute
+    ///
+    /// Times skipped when clocks go forward are left out, times repeated when
+    /// they go back are only used the first time.
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut time = after.naive_local();
+        loop {
+            time = self.next_naive(time)?;
+            if let Some(local) = Local.from_local_datetime(&time).earliest() {
+                if local > after {
+                    return Some(local);
+                }
+            }
+        }
+    }
+
+    /// Returns the first minute after `after` matching, in local time
+    fn next_naive(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut time = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = time + Duration::days(SEARCH_DAYS);
+        while time < limit {
+            let date = time.date();
+            if self.months & 1 << date.month() == 0 || !self.matches_day(date) {
+                time = (date + Duration::days(1)).and_hms_opt(0, 0, 0)?;
+            } else if self.hours & 1 << time.hour() == 0 {
+                time = date.and_hms_opt(time.hour(), 0, 0)? + Duration::hours(1);
+            } else if self.minutes & 1 << time.minute() == 0 {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = self.days & 1 << date.day() != 0;
+        let weekday = self.weekdays & 1 << date.weekday().num_days_from_sunday() != 0;
+        if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        }
+    }
+}
+
+/// Parses one field of a schedule into a bit per value
+///
+/// # Arguments
+///
+/// * `field` - The field, like `*/5` or `MON-FRI`
+/// * `min`, `max` - The values the field can take
+/// * `names` - Names of the values from `min` on, like JAN for 1
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |s: &str| -> Result<u32, String> {
+        let named = names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(s))
+            .map(|index| min + index as u32);
+        match named.map_or_else(|| s.parse().ok(), Some) {
+            Some(v) if (min..=max).contains(&v) => Ok(v),
+            _ => Err(format!("{} is not a value from {} to {}", s, min, max)),
+        }
+    };
+    let mut bits = 0;
+    for part in field.split(';') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => return Err(format!("invalid step in {}", part)),
+            },
+            None => (part, None),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (value(start)?, value(end)?)
+        } else {
+            // Like in cron, 5/15 means 5-max/15
+            let start = value(range)?;
+            (start, if step.is_some() { max } else { start })
+        };
+        if start > end {
+            return Err(format!("invalid range {}", range));
+        }
+        for v in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("{} doesn't have 5 fields", s));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7, &WEEKDAYS)?;
+        if weekdays & 1 << 7 != 0 {
+            weekdays = weekdays & !(1 << 7) | 1;
+        }
+        let schedule = Schedule {
+            source: s.to_string(),
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, &MONTHS)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        };
+        // Days like February 30 are accepted by the fields but never come
+        let start = NaiveDate::from_ymd_opt(2000, 1, 1)
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .unwrap();
+        if schedule.next_naive(start).is_none() {
+            return Err(format!("{} never matches", s));
+        }
+        Ok(schedule)
+    }
+}
+
+impl PartialEq for Schedule {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schedule;
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .and_then(|date| date.and_hms_opt(h, min, 0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_next_naive() {
+        let schedule: Schedule = "*/5 8-18 * * MON-FRI".parse().unwrap();
+        // Friday 2024-03-15
+        assert_eq!(
+            schedule.next_naive(at(2024, 3, 15, 9, 2)),
+            Some(at(2024, 3, 15, 9, 5))
+        );
+        assert_eq!(
+            schedule.next_naive(at(2024, 3, 15, 9, 5)),
+            Some(at(2024, 3, 15, 9, 10))
+        );
+        assert_eq!(
+            schedule.next_naive(at(2024, 3, 15, 18, 55)),
+            Some(at(2024, 3, 18, 8, 0))
+        );
+        let schedule: Schedule = "0;30 6 1 jan;jul 7".parse().unwrap();
+        // Either the 1st or a Sunday, 2024-01-07 is a Sunday
+        assert_eq!(
+            schedule.next_naive(at(2024, 1, 1, 6, 30)),
+            Some(at(2024, 1, 7, 6, 0))
+        );
+        assert_eq!(
+            schedule.next_naive(at(2024, 1, 28, 7, 0)),
+            Some(at(2024, 7, 1, 6, 0))
+        );
+        let leap: Schedule = "0 0 29 2 *".parse().unwrap();
+        assert_eq!(
+            leap.next_naive(at(2024, 3, 1, 0, 0)),
+            Some(at(2028, 2, 29, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        assert!("* * * *".parse::<Schedule>().is_err());
+        assert!("60 * * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("0 18-8 * * *".parse::<Schedule>().is_err());
+        assert!("0 0 30 FEB *".parse::<Schedule>().is_err());
+        assert!("0 0 * * SUN-XYZ".parse::<Schedule>().is_err());
+        let schedule: Schedule = "15 */6 * * *".parse().unwrap();
+        assert_eq!(schedule.to_string(), "15 */6 * * *");
+        assert_eq!(schedule, "15 */6 * * *".parse().unwrap());
+    }
+}
@@ -0,0 +1,528 @@
+//! Persistent per-job state kept between runs
+//!
+//! Each config entry gets its own file in the state directory, named after a
+//! hash of its SOURCE and TARGET locations so reordering the config file does
+//! not mix up the state of different jobs. The file holds one `key<TAB>value`
+//! pair per line and is replaced atomically on save.
+//!
+//! Next to it every job gets a `.status` file in the same format, holding the
+//! outcome of its last runs for monitoring. A single `traffic` file adds up
+//! the bytes moved per day and remote host over all jobs.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Key/value state of a single job
+#[derive(Debug)]
+pub struct JobState {
+    path: PathBuf,
+    values: BTreeMap<String, String>,
+}
+
+impl JobState {
+    /// Loads the state of a job, a missing file gives an empty state
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The state directory
+    /// * `job_key` - Stable identifier of the job, see crate::job_key
+    pub fn load(dir: &Path, job_key: &str) -> io::Result<JobState> {
+        JobState::load_path(dir.join(format!("{}.state", job_key)))
+    }
+
+    fn load_path(path: PathBuf) -> io::Result<JobState> {
+        let mut values = BTreeMap::new();
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    if let Some((key, value)) = line.split_once('\t') {
+                        values.insert(key.to_string(), value.to_string());
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        Ok(JobState { path, values })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.values.remove(key);
+    }
+
+    /// Keys starting with prefix, with the prefix stripped
+    pub fn keys_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> {
+        self.values
+            .keys()
+            .filter_map(move |key| key.strip_prefix(prefix))
+    }
+
+    /// Writes the state to a temporary file and renames it over the old one
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        for (key, value) in &self.values {
+            // Neither part may break the line format
+            let key = key.replace(['\t', '\n'], " ");
+            let value = value.replace(['\t', '\n'], " ");
+            writeln!(file, "{}\t{}", key, value)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+/// Whether a file is not newer than what only_new already delivered
+///
+/// Files with exactly the watermark mtime are compared by name, since several
+/// files can share the same second, and so are files delivered while an older
+/// one was left behind, see advance_watermark.
+pub fn delivered_before(state: &JobState, filename: &str, mtime: u64) -> bool {
+    if state.get(&format!("delivered.{}", filename)) == Some(mtime.to_string().as_str()) {
+        return true;
+    }
+    match state.get("newest_mtime").and_then(|v| v.parse::<u64>().ok()) {
+        Some(newest) => {
+            mtime < newest
+                || (mtime == newest
+                    && state.get(&format!("newest_name.{}", filename)).is_some())
+        }
+        None => false,
+    }
+}
+
+/// Remembers a file delivered by only_new until advance_watermark takes it in
+pub fn record_delivered(state: &mut JobState, filename: &str, mtime: u64) {
+    state.set(&format!("delivered.{}", filename), &mtime.to_string());
+}
+
+/// Moves the only_new watermark forward over the files delivered by a run
+///
+/// The watermark stays below the oldest file the run left undelivered, so
+/// the next run tries that file again. Files delivered after it are skipped
+/// by name until the watermark passes them.
+///
+/// # Arguments
+///
+/// * `state` - The state of the job
+/// * `below` - The mtime of the oldest file left undelivered, u64::MAX if none
+pub fn advance_watermark(state: &mut JobState, below: u64) {
+    let delivered: Vec<(String, u64)> = state
+        .keys_with_prefix("delivered.")
+        .filter_map(|name| {
+            let mtime = state.get(&format!("delivered.{}", name))?.parse().ok()?;
+            Some((name.to_string(), mtime))
+        })
+        .filter(|(_, mtime)| *mtime < below)
+        .collect();
+    let newest = state
+        .get("newest_mtime")
+        .and_then(|v| v.parse::<u64>().ok());
+    let mark = match delivered
+        .iter()
+        .map(|(_, mtime)| *mtime)
+        .chain(newest)
+        .max()
+    {
+        Some(mark) => mark,
+        None => return,
+    };
+    if newest != Some(mark) {
+        let old_names: Vec<String> = state
+            .keys_with_prefix("newest_name.")
+            .map(|name| format!("newest_name.{}", name))
+            .collect();
+        for key in old_names {
+            state.remove(&key);
+        }
+        state.set("newest_mtime", &mark.to_string());
+    }
+    for (name, mtime) in delivered {
+        state.remove(&format!("delivered.{}", name));
+        if mtime == mark {
+            state.set(&format!("newest_name.{}", name), "");
+        }
+    }
+}
+
+/// Whether a file with this content was already delivered under the same name
+pub fn content_delivered(state: &JobState, filename: &str, hash: &str) -> bool {
+    state.get(&format!("sha256.{}", filename)) == Some(hash)
+}
+
+/// Remembers the content of a delivered file for skip_unchanged
+pub fn record_content(state: &mut JobState, filename: &str, hash: &str) {
+    state.set(&format!("sha256.{}", filename), hash);
+}
+
+/// Counts one more run failing to deliver a file, for quarantine_after
+///
+/// A file whose modification time changed starts counting from one again.
+///
+/// # Returns
+///
+/// * The number of consecutive runs that failed to deliver the file
+pub fn record_failure(state: &mut JobState, filename: &str, mtime: u64) -> u32 {
+    let key = format!("failures.{}", filename);
+    let failures = match state.get(&key).and_then(|v| v.split_once(' ')) {
+        Some((seen, count)) if seen == mtime.to_string() => count.parse().unwrap_or(0) + 1,
+        _ => 1,
+    };
+    state.set(&key, &format!("{} {}", mtime, failures));
+    failures
+}
+
+/// Forgets the failed runs of a file once it was delivered
+pub fn clear_failures(state: &mut JobState, filename: &str) {
+    state.remove(&format!("failures.{}", filename));
+}
+
+/// Whether a file was quarantined and has not changed since
+pub fn quarantined(state: &JobState, filename: &str, mtime: u64) -> bool {
+    state.get(&format!("quarantined.{}", filename)) == Some(mtime.to_string().as_str())
+}
+
+/// Puts a file on the skip list of a job without quarantine_path
+pub fn record_quarantined(state: &mut JobState, filename: &str, mtime: u64) {
+    clear_failures(state, filename);
+    state.set(&format!("quarantined.{}", filename), &mtime.to_string());
+}
+
+/// Remembers which version of a file a batch upload to `.name.<pid>.tmp` was of
+///
+/// resume_uploads only appends to a partial upload of the same version.
+pub fn record_upload(state: &mut JobState, target_name: &str, pid: u32, mtime: u64, size: u64) {
+    state.set(
+        &format!("upload.{}", target_name),
+        &format!("{} {} {}", pid, mtime, size),
+    );
+}
+
+/// The process id in the name of an earlier upload of this version of a file
+pub fn partial_upload(state: &JobState, target_name: &str, mtime: u64, size: u64) -> Option<u32> {
+    let value = state.get(&format!("upload.{}", target_name))?;
+    let (pid, origin) = value.split_once(' ')?;
+    (origin == format!("{} {}", mtime, size))
+        .then(|| pid.parse().ok())
+        .flatten()
+}
+
+/// Forgets the upload of a file once the batch renamed it into place
+pub fn clear_upload(state: &mut JobState, target_name: &str) {
+    state.remove(&format!("upload.{}", target_name));
+}
+
+/// Name of the file holding the bytes moved per day and remote host
+pub const TRAFFIC: &str = "traffic";
+
+/// Adds the bytes moved by a run to the traffic file of the state directory
+///
+/// Keys are `<day> <host>`, values `<downloaded> <uploaded>` in bytes.
+///
+/// # Arguments
+///
+/// * `dir` - The state directory
+/// * `day` - The day of the run, like 2023-01-31
+/// * `traffic` - Bytes downloaded from and uploaded to each host
+pub fn record_traffic(
+    dir: &Path,
+    day: &str,
+    traffic: &BTreeMap<String, (u64, u64)>,
+) -> io::Result<()> {
+    let mut totals = JobState::load_path(dir.join(TRAFFIC))?;
+    for (host, (downloaded, uploaded)) in traffic {
+        let key = format!("{} {}", day, host);
+        let (old_down, old_up) = totals.get(&key).map_or((0, 0), parse_traffic);
+        let value = format!("{} {}", old_down + downloaded, old_up + uploaded);
+        totals.set(&key, &value);
+    }
+    totals.save()
+}
+
+/// Reads the traffic file of the state directory, see record_traffic
+///
+/// # Returns
+///
+/// * Day, host, bytes downloaded and bytes uploaded, sorted by day and host
+pub fn load_traffic(dir: &Path) -> io::Result<Vec<(String, String, u64, u64)>> {
+    let totals = JobState::load_path(dir.join(TRAFFIC))?;
+    Ok(totals
+        .keys_with_prefix("")
+        .filter_map(|key| {
+            let (day, host) = key.split_once(' ')?;
+            let (downloaded, uploaded) = parse_traffic(totals.get(key)?);
+            Some((day.to_string(), host.to_string(), downloaded, uploaded))
+        })
+        .collect())
+}
+
+fn parse_traffic(value: &str) -> (u64, u64) {
+    let mut bytes = value.split(' ').map(|n| n.parse().unwrap_or(0));
+    (bytes.next().unwrap_or(0), bytes.next().unwrap_or(0))
+}
+
+/// Path of the copy of the last version of a config file that parsed
+///
+/// Config files sharing a state directory each get their own copy.
+///
+/// # Arguments
+///
+/// * `dir` - The state directory
+/// * `config_key` - Stable identifier of the config file, see crate::config_key
+pub fn good_config_path(dir: &Path, config_key: &str) -> PathBuf {
+    dir.join(format!("last-good-{}.conf", config_key))
+}
+
+/// Keeps a copy of a config file that parsed, to fall back to when a later
+/// edit breaks it
+///
+/// The copy holds the passwords of the config file, so it is created
+/// readable by its owner only.
+pub fn save_good_config(dir: &Path, config_key: &str, contents: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = good_config_path(dir, config_key);
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let _ = fs::remove_file(&tmp_path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)
+}
+
+/// Records the outcome of a run in the status file of a job
+///
+/// A successful run sets `last_success` to `now` and `last_transferred` to
+/// the number of files it transferred, a failed one sets `last_error`. Both
+/// set `last_run` and `last_result`, and `job` names the job for humans.
+///
+/// # Arguments
+///
+/// * `dir` - The state directory
+/// * `job_key` - Stable identifier of the job, see crate::job_key
+/// * `job` - Description of the job
+/// * `now` - Time of the run in seconds since the epoch
+/// * `outcome` - Number of transferred files, or why the job failed
+pub fn record_run(
+    dir: &Path,
+    job_key: &str,
+    job: &str,
+    now: u64,
+    outcome: Result<i32, &str>,
+) -> io::Result<()> {
+    let mut status = JobState::load_path(dir.join(format!("{}.status", job_key)))?;
+    status.set("job", job);
+    status.set("last_run", &now.to_string());
+    match outcome {
+        Ok(transferred) => {
+            status.set("last_result", "success");
+            status.set("last_success", &now.to_string());
+            status.set("last_transferred", &transferred.to_string());
+        }
+        Err(error) => {
+            status.set("last_result", "failure");
+            status.set("last_error", error);
+        }
+    }
+    status.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        advance_watermark, clear_failures, clear_upload, content_delivered, delivered_before,
+        good_config_path, load_traffic, partial_upload, quarantined, record_content,
+        record_delivered, record_failure, record_quarantined, record_run, record_traffic,
+        record_upload, save_good_config, JobState,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_job_state_roundtrip() {
+        let dir = tempdir().unwrap();
+        let mut state = JobState::load(dir.path(), "job").unwrap();
+        assert_eq!(state.get("newest_mtime"), None);
+
+        state.set("newest_mtime", "1700000000");
+        state.set("newest_name.a.xml", "");
+        state.set("newest_name.b.xml", "");
+        state.save().unwrap();
+
+        let mut state = JobState::load(dir.path(), "job").unwrap();
+        assert_eq!(state.get("newest_mtime"), Some("1700000000"));
+        let names: Vec<&str> = state.keys_with_prefix("newest_name.").collect();
+        assert_eq!(names, vec!["a.xml", "b.xml"]);
+
+        state.remove("newest_name.a.xml");
+        assert_eq!(state.keys_with_prefix("newest_name.").count(), 1);
+    }
+
+    #[test]
+    fn test_only_new_watermark() {
+        let dir = tempdir().unwrap();
+        let mut state = JobState::load(dir.path(), "job").unwrap();
+        assert!(!delivered_before(&state, "a.xml", 100));
+
+        record_delivered(&mut state, "a.xml", 100);
+        advance_watermark(&mut state, u64::MAX);
+        assert!(delivered_before(&state, "a.xml", 100));
+        assert!(delivered_before(&state, "old.xml", 99));
+        // Same second, different file: not delivered yet
+        assert!(!delivered_before(&state, "b.xml", 100));
+
+        record_delivered(&mut state, "b.xml", 100);
+        record_delivered(&mut state, "c.xml", 101);
+        advance_watermark(&mut state, u64::MAX);
+        assert!(delivered_before(&state, "b.xml", 100));
+        assert!(delivered_before(&state, "c.xml", 101));
+        assert_eq!(state.keys_with_prefix("newest_name.").count(), 1);
+        assert_eq!(state.keys_with_prefix("delivered.").count(), 0);
+    }
+
+    #[test]
+    fn test_only_new_out_of_order() {
+        let dir = tempdir().unwrap();
+        let mut state = JobState::load(dir.path(), "job").unwrap();
+        // Newest first, the middle file fails
+        record_delivered(&mut state, "c.xml", 300);
+        assert!(!delivered_before(&state, "b.xml", 200));
+        assert!(!delivered_before(&state, "a.xml", 100));
+        record_delivered(&mut state, "a.xml", 100);
+        advance_watermark(&mut state, 200);
+        assert!(delivered_before(&state, "a.xml", 100));
+        assert!(!delivered_before(&state, "b.xml", 200));
+        assert!(delivered_before(&state, "c.xml", 300));
+        // A new file in the same second as one delivered is not skipped
+        assert!(!delivered_before(&state, "d.xml", 300));
+
+        // The next run delivers the failed file
+        record_delivered(&mut state, "b.xml", 200);
+        advance_watermark(&mut state, u64::MAX);
+        assert!(delivered_before(&state, "b.xml", 200));
+        assert!(delivered_before(&state, "c.xml", 300));
+        assert_eq!(state.get("newest_mtime"), Some("300"));
+        assert_eq!(state.keys_with_prefix("delivered.").count(), 0);
+    }
+
+    #[test]
+    fn test_content_delivered() {
+        let dir = tempdir().unwrap();
+        let mut state = JobState::load(dir.path(), "job").unwrap();
+        assert!(!content_delivered(&state, "a.xml", "1234"));
+
+        record_content(&mut state, "a.xml", "1234");
+        assert!(content_delivered(&state, "a.xml", "1234"));
+        assert!(!content_delivered(&state, "a.xml", "5678"));
+        assert!(!content_delivered(&state, "b.xml", "1234"));
+    }
+
+    #[test]
+    fn test_partial_upload() {
+        let dir = tempdir().unwrap();
+        let mut state = JobState::load(dir.path(), "job").unwrap();
+        assert_eq!(partial_upload(&state, "a.xml", 100, 10), None);
+
+        record_upload(&mut state, "a.xml", 4242, 100, 10);
+        assert_eq!(partial_upload(&state, "a.xml", 100, 10), Some(4242));
+        // Another version of the file must not be appended to it
+        assert_eq!(partial_upload(&state, "a.xml", 200, 10), None);
+        assert_eq!(partial_upload(&state, "a.xml", 100, 11), None);
+
+        clear_upload(&mut state, "a.xml");
+        assert_eq!(partial_upload(&state, "a.xml", 100, 10), None);
+    }
+
+    #[test]
+    fn test_failures_and_quarantine() {
+        let dir = tempdir().unwrap();
+        let mut state = JobState::load(dir.path(), "job").unwrap();
+        assert_eq!(record_failure(&mut state, "a.xml", 100), 1);
+        assert_eq!(record_failure(&mut state, "a.xml", 100), 2);
+        // A new version of the file gets a fresh start
+        assert_eq!(record_failure(&mut state, "a.xml", 200), 1);
+        clear_failures(&mut state, "a.xml");
+        assert_eq!(record_failure(&mut state, "a.xml", 200), 1);
+
+        assert!(!quarantined(&state, "a.xml", 200));
+        record_quarantined(&mut state, "a.xml", 200);
+        assert!(quarantined(&state, "a.xml", 200));
+        assert!(!quarantined(&state, "a.xml", 300));
+        assert_eq!(state.get("failures.a.xml"), None);
+    }
+
+    #[test]
+    fn test_record_run() {
+        let dir = tempdir().unwrap();
+        record_run(dir.path(), "job", "a -> b", 100, Ok(3)).unwrap();
+        record_run(dir.path(), "job", "a -> b", 200, Err("connection refused")).unwrap();
+
+        let status = std::fs::read_to_string(dir.path().join("job.status")).unwrap();
+        assert_eq!(
+            status,
+            "job\ta -> b\nlast_error\tconnection refused\nlast_result\tfailure\n\
+             last_run\t200\nlast_success\t100\nlast_transferred\t3\n"
+        );
+        // The state file of the job is a different file
+        assert_eq!(JobState::load(dir.path(), "job").unwrap().get("job"), None);
+    }
+
+    #[test]
+    fn test_record_traffic() {
+        let dir = tempdir().unwrap();
+        let mut traffic = std::collections::BTreeMap::new();
+        traffic.insert("a.example.com".to_string(), (100, 0));
+        traffic.insert("b.example.com".to_string(), (0, 100));
+        record_traffic(dir.path(), "2023-01-31", &traffic).unwrap();
+        record_traffic(dir.path(), "2023-01-31", &traffic).unwrap();
+        record_traffic(dir.path(), "2023-02-01", &traffic).unwrap();
+
+        let day = |day: &str, host: &str, down, up| (day.to_string(), host.to_string(), down, up);
+        assert_eq!(
+            load_traffic(dir.path()).unwrap(),
+            vec![
+                day("2023-01-31", "a.example.com", 200, 0),
+                day("2023-01-31", "b.example.com", 0, 200),
+                day("2023-02-01", "a.example.com", 100, 0),
+                day("2023-02-01", "b.example.com", 0, 100),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_save_good_config() {
+        let dir = tempdir().unwrap();
+        save_good_config(dir.path(), "a", "old\n").unwrap();
+        save_good_config(dir.path(), "a", "new\n").unwrap();
+        // Another config file using the same state directory
+        save_good_config(dir.path(), "b", "other\n").unwrap();
+
+        let path = good_config_path(dir.path(), "a");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new\n");
+        let other = good_config_path(dir.path(), "b");
+        assert_eq!(std::fs::read_to_string(other).unwrap(), "other\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! A small FTP server for tests running whole jobs
+//!
+//! It serves a local directory to any login, lists it with MLSD and knows
+//! just the commands a job sends. Anything else gets 502.
+
+use chrono::{DateTime, Utc};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// A server answering in threads until the test ends
+pub struct FakeFtp {
+    pub port: u16,
+    /// Number of connections still to turn away as too busy
+    pub busy: Arc<AtomicUsize>,
+}
+
+impl FakeFtp {
+    /// Starts serving root on a free port of 127.0.0.1
+    pub fn start(root: &Path) -> FakeFtp {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let root = root.to_path_buf();
+        let busy = Arc::new(AtomicUsize::new(0));
+        let turn_away = Arc::clone(&busy);
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let too_busy = turn_away
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok();
+                if too_busy {
+                    let _ = stream.write_all(b"421 Too many connections, try again later\r\n");
+                    continue;
+                }
+                let root = root.clone();
+                thread::spawn(move || {
+                    let _ = Session::new(root, stream).and_then(|session| session.run());
+                });
+            }
+        });
+        FakeFtp { port, busy }
+    }
+}
+
+struct Session {
+    root: PathBuf,
+    /// The current directory as the client sees it
+    cwd: String,
+    reader: BufReader<TcpStream>,
+    control: TcpStream,
+    pasv: Option<TcpListener>,
+    rest: u64,
+    rename_from: Option<PathBuf>,
+}
+
+impl Session {
+    fn new(root: PathBuf, control: TcpStream) -> io::Result<Session> {
+        Ok(Session {
+            root,
+            cwd: "/".to_string(),
+            reader: BufReader::new(control.try_clone()?),
+            control,
+            pasv: None,
+            rest: 0,
+            rename_from: None,
+        })
+    }
+
+    fn reply(&mut self, line: &str) -> io::Result<()> {
+        self.control.write_all(format!("{}\r\n", line).as_bytes())
+    }
+
+    /// The local path and the path the client sees of name
+    fn path(&self, name: &str) -> (PathBuf, String) {
+        let joined = if name.starts_with('/') {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.cwd, name)
+        };
+        let mut parts: Vec<&str> = Vec::new();
+        for part in joined.split('/') {
+            match part {
+                "" | "." => (),
+                ".." => {
+                    parts.pop();
+                }
+                part => parts.push(part),
+            }
+        }
+        let path = parts
+            .iter()
+            .fold(self.root.clone(), |path, part| path.join(part));
+        (path, format!("/{}", parts.join("/")))
+    }
+
+    fn data_connection(&mut self) -> io::Result<TcpStream> {
+        let listener = self.pasv.take().ok_or(io::ErrorKind::NotConnected)?;
+        Ok(listener.accept()?.0)
+    }
+
+    fn run(mut self) -> io::Result<()> {
+        self.reply("220 Ready")?;
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+            let command = command.to_ascii_uppercase();
+            if command == "QUIT" {
+                return self.reply("221 Bye");
+            }
+            if let Err(e) = self.dispatch(&command, arg) {
+                self.reply(&format!("550 {}", e))?;
+            }
+        }
+    }
+
+    fn dispatch(&mut self, command: &str, arg: &str) -> io::Result<()> {
+        match command {
+            "USER" => self.reply("331 Password required"),
+            "PASS" => self.reply("230 Logged in"),
+            "TYPE" | "NOOP" | "OPTS" | "CLNT" => self.reply("200 OK"),
+            "FEAT" => self
+                .reply("211-Features:\r\n MDTM\r\n SIZE\r\n MLST type*;size*;modify*;\r\n211 End"),
+            "PWD" => {
+                let cwd = self.cwd.clone();
+                self.reply(&format!("257 \"{}\"", cwd))
+            }
+            "CWD" => {
+                let (path, cwd) = self.path(arg);
+                if !path.is_dir() {
+                    return self.reply("550 No such directory");
+                }
+                self.cwd = cwd;
+                self.reply("250 OK")
+            }
+            "PASV" => {
+                let listener = TcpListener::bind("127.0.0.1:0")?;
+                let port = listener.local_addr()?.port();
+                self.pasv = Some(listener);
+                self.reply(&format!(
+                    "227 Entering Passive Mode (127,0,0,1,{},{})",
+                    port >> 8,
+                    port & 255
+                ))
+            }
+            "MLSD" | "NLST" => {
+                let (dir, _) = self.path(arg);
+                let mut names: Vec<_> = fs::read_dir(dir)?.flatten().collect();
+                names.sort_by_key(|entry| entry.file_name());
+                let mut listing = String::new();
+                for entry in names {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    if command == "NLST" {
+                        listing.push_str(&format!("{}\r\n", name));
+                        continue;
+                    }
+                    let metadata = entry.metadata()?;
+                    let kind = if metadata.is_dir() { "dir" } else { "file" };
+                    let modified: DateTime<Utc> = metadata.modified()?.into();
+                    listing.push_str(&format!(
+                        "type={};size={};modify={}; {}\r\n",
+                        kind,
+                        metadata.len(),
+                        modified.format("%Y%m%d%H%M%S"),
+                        name
+                    ));
+                }
+                self.reply("150 Here comes the listing")?;
+                self.data_connection()?.write_all(listing.as_bytes())?;
+                self.reply("226 Done")
+            }
+            "MDTM" => {
+                let modified: DateTime<Utc> = fs::metadata(self.path(arg).0)?.modified()?.into();
+                self.reply(&format!("213 {}", modified.format("%Y%m%d%H%M%S")))
+            }
+            "SIZE" => {
+                let size = fs::metadata(self.path(arg).0)?.len();
+                self.reply(&format!("213 {}", size))
+            }
+            "REST" => {
+                self.rest = arg.parse().unwrap_or(0);
+                self.reply("350 Restarting")
+            }
+            "RETR" => {
+                let data = fs::read(self.path(arg).0)?;
+                let offset = (std::mem::take(&mut self.rest) as usize).min(data.len());
+                self.reply(&format!(
+                    "150 Opening data connection ({} bytes)",
+                    data.len()
+                ))?;
+                self.data_connection()?.write_all(&data[offset..])?;
+                self.reply("226 Transfer complete")
+            }
+            "STOR" | "APPE" => {
+                let (path, _) = self.path(arg);
+                self.reply("150 Ok to send data")?;
+                let mut data = Vec::new();
+                self.data_connection()?.read_to_end(&mut data)?;
+                OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(command == "APPE")
+                    .truncate(command == "STOR")
+                    .open(path)?
+                    .write_all(&data)?;
+                self.reply("226 Transfer complete")
+            }
+            "DELE" => {
+                fs::remove_file(self.path(arg).0)?;
+                self.reply("250 Deleted")
+            }
+            "MKD" => {
+                let (path, dir) = self.path(arg);
+                fs::create_dir(path)?;
+                self.reply(&format!("257 \"{}\" created", dir))
+            }
+            "RNFR" => {
+                let (path, _) = self.path(arg);
+                fs::metadata(&path)?;
+                self.rename_from = Some(path);
+                self.reply("350 Ready for RNTO")
+            }
+            "RNTO" => {
+                let from = self.rename_from.take().ok_or(io::ErrorKind::NotFound)?;
+                fs::rename(from, self.path(arg).0)?;
+                self.reply("250 Renamed")
+            }
+            _ => self.reply("502 Command not implemented"),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Looking up newer releases, see the check-update subcommand
+//!
+//! The release is fetched with curl, HTTPS is not worth a TLS stack in a
+//! file mover. The URL must return JSON like the GitHub releases API does,
+//! with the version in `tag_name`, the changelog in `body` and a link to the
+//! release in `html_url`.
+
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::Path;
+use std::process;
+
+/// The latest release of iftpfm2 on GitHub
+pub const DEFAULT_UPDATE_URL: &str =
+    "https://api.github.com/repos/burbilog/iftpfm2/releases/latest";
+
+/// Seconds curl may take to fetch the release
+const FETCH_TIMEOUT: u64 = 20;
+
+/// File in the state directory holding the outcome of the last lookup
+pub const LAST_CHECK: &str = "update-check";
+
+/// Seconds a lookup is reused by latest_version_cached
+const CHECK_INTERVAL: u64 = 24 * 3600;
+
+/// A published version of the program
+#[derive(Debug, PartialEq)]
+pub struct Release {
+    /// The version, like v2.1.0 or 2.1.0
+    pub version: String,
+    /// What changed, empty if the release has no description
+    pub changelog: String,
+    /// Web page of the release
+    pub url: Option<String>,
+}
+
+/// Fetches the description of the latest release
+///
+/// # Arguments
+///
+/// * `url` - Where the release is described, see DEFAULT_UPDATE_URL
+/// * `user_agent` - Sent to the server, GitHub refuses requests without one
+pub fn fetch_release(url: &str, user_agent: &str) -> io::Result<Release> {
+    let output = process::Command::new("curl")
+        .args(["-fsSL", "--max-time", &FETCH_TIMEOUT.to_string()])
+        .args([
+            "-A",
+            user_agent,
+            "-H",
+            "Accept: application/vnd.github+json",
+        ])
+        .arg(url)
+        .output()
+        .map_err(|e| Error::new(e.kind(), format!("can't run curl: {}", e)))?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        let message = format!("curl failed ({}): {}", output.status, message.trim());
+        return Err(Error::other(message));
+    }
+    parse_release(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("no tag_name in the reply from {}", url),
+        )
+    })
+}
+
+/// Looks up the version of the latest release at most once a day
+///
+/// The time, URL and outcome of the last lookup are kept in `dir`, so the
+/// startup notice doesn't ask the server on every run. A failed lookup
+/// isn't repeated within the day either.
+///
+/// # Arguments
+///
+/// * `dir` - The state directory
+/// * `now` - The current time in seconds since the epoch
+///
+/// # Returns
+///
+/// * The latest version, None if the last lookup failed less than a day ago
+pub fn latest_version_cached(
+    url: &str,
+    user_agent: &str,
+    dir: &Path,
+    now: u64,
+) -> io::Result<Option<String>> {
+    let path = dir.join(LAST_CHECK);
+    // <time> TAB <url> TAB <version>, the version is empty after a failure
+    if let Ok(contents) = fs::read_to_string(&path) {
+        let mut fields = contents.trim_end_matches('\n').splitn(3, '\t');
+        let checked = fields.next().and_then(|time| time.parse::<u64>().ok());
+        if let (Some(checked), Some(checked_url), Some(version)) =
+            (checked, fields.next(), fields.next())
+        {
+            if checked_url == url && now.saturating_sub(checked) < CHECK_INTERVAL {
+                return Ok(Some(version.to_string()).filter(|v| !v.is_empty()));
+            }
+        }
+    }
+    let fetched = fetch_release(url, user_agent);
+    let version = fetched
+        .as_ref()
+        .map_or("", |release| release.version.as_str());
+    fs::create_dir_all(dir)?;
+    fs::write(&path, format!("{}\t{}\t{}\n", now, url, version))?;
+    fetched.map(|release| Some(release.version))
+}
+
+/// Reads a release from a GitHub style JSON description, None without tag_name
+pub fn parse_release(json: &str) -> Option<Release> {
+    Some(Release {
+        version: json_string_field(json, "tag_name")?,
+        changelog: json_string_field(json, "body").unwrap_or_default(),
+        url: json_string_field(json, "html_url"),
+    })
+}
+
+/// Whether `latest` is a higher version than `current`
+///
+/// Versions are compared by their dot separated numbers, a leading v and
+/// anything after the numbers of a part (like -rc1) are ignored.
+pub fn is_newer(current: &str, latest: &str) -> bool {
+    let current = version_numbers(current);
+    let latest = version_numbers(latest);
+    let len = current.len().max(latest.len());
+    let padded = |numbers: &[u64]| -> Vec<u64> {
+        let mut numbers = numbers.to_vec();
+        numbers.resize(len, 0);
+        numbers
+    };
+    padded(&latest) > padded(&current)
+}
+
+fn version_numbers(version: &str) -> Vec<u64> {
+    let version = version.trim();
+    let version = version.strip_prefix(['v', 'V']).unwrap_or(version);
+    version
+        .split('.')
+        .map(|part| {
+            let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse().unwrap_or(0)
+        })
+        .collect()
+}
+
+/// Finds a top level string field of a JSON object, good enough for the
+/// flat release description
+fn json_string_field(json: &str, name: &str) -> Option<String> {
+    let key = format!("\"{}\"", name);
+    let mut rest = json;
+    loop {
+        let at = rest.find(&key)?;
+        rest = &rest[at + key.len()..];
+        if let Some(value) = rest.trim_start().strip_prefix(':') {
+            return json_string(value.trim_start());
+        }
+    }
+}
+
+/// Decodes the JSON string at the start of `text`, None if there is none
+fn json_string(text: &str) -> Option<String> {
+    let mut chars = text.strip_prefix('"')?.chars();
+    let mut value = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                'b' => value.push('\u{8}'),
+                'f' => value.push('\u{c}'),
+                'u' => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    let mut code = u32::from_str_radix(&code, 16).ok()?;
+                    // Characters outside the BMP come as a surrogate pair
+                    if (0xd800..0xdc00).contains(&code) && chars.as_str().starts_with("\\u") {
+                        let low: String = chars.by_ref().skip(2).take(4).collect();
+                        let low = u32::from_str_radix(&low, 16).ok()?;
+                        code =
+                            0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                    }
+                    value.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_newer, latest_version_cached, parse_release, Release, LAST_CHECK};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_release() {
+        let json = r#"{
+  "url": "https://api.github.com/repos/burbilog/iftpfm2/releases/1",
+  "html_url": "https://github.com/burbilog/iftpfm2/releases/tag/v2.1.0",
+  "tag_name" : "v2.1.0",
+  "name": "2.1.0",
+  "body": "* \"quoted\" fix\r\n* café \ud83d\ude80\\o/"
+}"#;
+        assert_eq!(
+            parse_release(json),
+            Some(Release {
+                version: "v2.1.0".to_string(),
+                changelog: "* \"quoted\" fix\r\n* café 🚀\\o/".to_string(),
+                url: Some("https://github.com/burbilog/iftpfm2/releases/tag/v2.1.0".to_string()),
+            })
+        );
+        assert_eq!(parse_release(r#"{"message": "Not Found"}"#), None);
+    }
+
+    #[test]
+    fn test_latest_version_cached() {
+        let dir = tempdir().unwrap();
+        let url = "http://127.0.0.1:9/releases/latest";
+        let path = dir.path().join(LAST_CHECK);
+        std::fs::write(&path, format!("1000\t{}\tv2.1.0\n", url)).unwrap();
+        let cached = latest_version_cached(url, "test", dir.path(), 2000).unwrap();
+        assert_eq!(cached, Some("v2.1.0".to_string()));
+        // A lookup that failed today is not repeated
+        std::fs::write(&path, format!("1000\t{}\t\n", url)).unwrap();
+        assert_eq!(
+            latest_version_cached(url, "test", dir.path(), 2000).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("2.0.2", "v2.0.3"));
+        assert!(is_newer("2.0.2", "2.1"));
+        assert!(is_newer("2.0.9", "2.0.10"));
+        assert!(!is_newer("2.0.2", "v2.0.2"));
+        assert!(!is_newer("2.0.2", "2.0"));
+        assert!(!is_newer("2.0.2", "2.0.2-rc1"));
+        assert!(!is_newer("2.0.2", "1.9.9"));
+    }
+}